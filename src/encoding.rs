@@ -0,0 +1,103 @@
+//! Varint/zigzag primitives backing the wire encoding of `IBF` and
+//! `Estimator` (see `to_bytes`/`from_bytes`/`write_to` on each). Kept in one
+//! place so both types agree on the same integer encoding.
+
+use std::fmt;
+
+/// Bumped whenever the wire format changes incompatibly.
+pub const WIRE_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEof,
+    UnsupportedVersion { found: u8 },
+    /// The encoded `(N, K)` doesn't match the `IBF` we're decoding into, so
+    /// the two peers don't agree on parameters.
+    IbfParamMismatch { expected: (u64, u64), found: (u64, u64) },
+    /// The encoded `(N, K, S)` doesn't match the `Estimator` we're decoding
+    /// into.
+    EstimatorParamMismatch {
+        expected: (u64, u64, u64),
+        found: (u64, u64, u64),
+    },
+    /// The encoded `IBF` cell count doesn't match any size on `SizedIbf`'s
+    /// ladder.
+    UnsupportedSize { found: u64 },
+    /// The encoded `Message` tag byte isn't one this version understands.
+    UnsupportedTag { found: u8 },
+    /// A varint ran past the 10 bytes a `u64` can ever need, so it can't be
+    /// decoded without overflowing the shift that decodes it. Distinguished
+    /// from `UnexpectedEof` because the input wasn't short, it was corrupt.
+    MalformedVarint,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof => write!(f, "unexpected end of input"),
+            Self::UnsupportedVersion { found } => {
+                write!(f, "unsupported wire version {found}, expected {WIRE_VERSION}")
+            }
+            Self::IbfParamMismatch { expected, found } => write!(
+                f,
+                "IBF parameter mismatch: expected (N, K) = {expected:?}, found {found:?}"
+            ),
+            Self::EstimatorParamMismatch { expected, found } => write!(
+                f,
+                "estimator parameter mismatch: expected (N, K, S) = {expected:?}, found {found:?}"
+            ),
+            Self::UnsupportedSize { found } => {
+                write!(f, "unsupported IBF cell count {found}")
+            }
+            Self::UnsupportedTag { found } => {
+                write!(f, "unsupported message tag {found}")
+            }
+            Self::MalformedVarint => write!(f, "malformed varint: too many continuation bytes"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Counts cluster near `-K..=K`, so zigzag-encoding the signed count before
+/// varint-encoding it keeps small magnitudes (including negatives) cheap.
+pub(crate) fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub(crate) fn zigzag_decode(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Reads a varint from the front of `bytes`, returning the value and how
+/// many bytes it occupied. Rejects a malformed varint (more continuation
+/// bytes than a `u64` could ever need) rather than overflowing the shift
+/// that decodes it -- `bytes` comes from another peer over `from_bytes`, so
+/// a corrupt or hostile message must return `DecodeError`, not panic.
+pub(crate) fn read_varint(bytes: &[u8]) -> Result<(u64, usize), DecodeError> {
+    let mut value = 0u64;
+    let mut shift = 0u32;
+    for (i, &byte) in bytes.iter().enumerate() {
+        if shift >= 64 {
+            return Err(DecodeError::MalformedVarint);
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+        shift += 7;
+    }
+    Err(DecodeError::UnexpectedEof)
+}
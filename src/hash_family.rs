@@ -0,0 +1,84 @@
+use std::hash::{Hash, Hasher};
+
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::ibf::HASH_SIZE;
+
+/// The two hash functions reconciliation needs: one to turn an arbitrary
+/// element into a fixed-size identifier (`id_hash`), and one to checksum that
+/// identifier so a peeled cell can be verified against XOR corruption
+/// (`cell_checksum`).
+///
+/// Methods take `&self` rather than being stateless, so a `HashFamily` can
+/// carry runtime state such as a secret key: e.g. a SipHasher keyed per
+/// session to harden against an adversary crafting colliding identifiers.
+/// `IBF`/`Estimator` store a `HashFamily` instance (see `with_hasher`)
+/// instead of assuming one can always be conjured from nothing.
+///
+/// Swapping the `HashFamily` lets callers trade off properties like collision
+/// resistance, keyed hardening against adversarial inputs, interop with a
+/// standard digest, or a shorter identifier to save bandwidth, all without
+/// touching `IBF` or `Estimator` themselves.
+pub trait HashFamily: Clone + Copy {
+    fn id_hash(&self, data: &[u8]) -> [u8; HASH_SIZE];
+    fn cell_checksum(&self, id: &[u8; HASH_SIZE]) -> u64;
+}
+
+/// The hash family this crate used before it became pluggable: BLAKE3 for
+/// identifiers, xxHash3 for the cell checksum. Kept as the default so
+/// existing call sites keep working unchanged. Stateless, so it's also
+/// `Default`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Blake3Xxh3;
+
+impl HashFamily for Blake3Xxh3 {
+    fn id_hash(&self, data: &[u8]) -> [u8; HASH_SIZE] {
+        *blake3::hash(data).as_bytes()
+    }
+
+    fn cell_checksum(&self, id: &[u8; HASH_SIZE]) -> u64 {
+        xxh3_64(id)
+    }
+}
+
+/// Bridges an arbitrary `std::hash::Hash` value into this crate's
+/// `[u8; HASH_SIZE]` identifier space by feeding it through `hash_family`'s
+/// `id_hash`, mirroring how the standard library's `Hash`/`Hasher` pair work
+/// together.
+struct IdHasher<'a, HF: HashFamily> {
+    bytes: Vec<u8>,
+    hash_family: &'a HF,
+}
+
+impl<'a, HF: HashFamily> IdHasher<'a, HF> {
+    fn new(hash_family: &'a HF) -> Self {
+        Self {
+            bytes: Vec::new(),
+            hash_family,
+        }
+    }
+
+    fn finish_id(&self) -> [u8; HASH_SIZE] {
+        self.hash_family.id_hash(&self.bytes)
+    }
+}
+
+impl<'a, HF: HashFamily> Hasher for IdHasher<'a, HF> {
+    fn write(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash_family.cell_checksum(&self.finish_id())
+    }
+}
+
+/// Hashes an arbitrary `Hash` value down to this crate's identifier size,
+/// using `hash_family` as the underlying hash family. Backs `insert_value`/
+/// `remove_value` on `IBF` and `Estimator`, so callers with a `HashSet<T>`
+/// don't have to serialize `T` to bytes themselves.
+pub(crate) fn hash_value<A: Hash, HF: HashFamily>(item: &A, hash_family: &HF) -> [u8; HASH_SIZE] {
+    let mut hasher = IdHasher::new(hash_family);
+    item.hash(&mut hasher);
+    hasher.finish_id()
+}
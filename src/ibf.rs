@@ -1,16 +1,31 @@
 use std::{
+    collections::VecDeque,
     iter,
     ops::{Add, AddAssign, Sub, SubAssign},
 };
 
 use blake3::Hash;
-use xxhash_rust::xxh3::{xxh3_64, xxh3_64_with_seed};
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+use crate::encoding::{
+    read_varint, write_varint, zigzag_decode, zigzag_encode, DecodeError, WIRE_VERSION,
+};
+use crate::hash_family::{hash_value, Blake3Xxh3, HashFamily};
 
 pub const HASH_SIZE: usize = 32;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct IBF<const N: usize, const K: usize = 4> {
-    pub cells: [Cell; N],
+/// `cells` is boxed rather than an inline `[Cell; N]` so that filters near
+/// the top of the size ladder (`IBF<4096, _>` is ~196 KB) are heap-allocated
+/// directly: `vec![Cell::default(); N]` fills the heap buffer in place
+/// without ever materializing the whole array on the stack, and `IBF` itself
+/// is just a pointer plus its `hasher`, no matter how large `N` is. That
+/// keeps `SizedIbf` (which holds `IBF`s directly) and by-value moves of `IBF`
+/// cheap regardless of size, instead of relying on the optimizer to elide a
+/// large stack copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IBF<const N: usize, const K: usize = 4, H: HashFamily = Blake3Xxh3> {
+    pub cells: Box<[Cell; N]>,
+    pub hasher: H,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -36,8 +51,8 @@ impl PureCell {
 }
 
 impl Cell {
-    pub fn new(id: [u8; HASH_SIZE]) -> Self {
-        let hash = xxh3_64(&id);
+    pub fn new<H: HashFamily>(id: [u8; HASH_SIZE], hasher: &H) -> Self {
+        let hash = hasher.cell_checksum(&id);
         Self { id, hash, count: 1 }
     }
 
@@ -45,17 +60,17 @@ impl Cell {
         self.count == 0
     }
 
-    pub fn get_if_pure(&self) -> Option<PureCell> {
+    pub fn get_if_pure<H: HashFamily>(&self, hasher: &H) -> Option<PureCell> {
         match self.count {
             -1 => {
-                if self.hash_matches() {
+                if self.hash_matches(hasher) {
                     Some(PureCell::Neg(self.id))
                 } else {
                     None
                 }
             }
             1 => {
-                if self.hash_matches() {
+                if self.hash_matches(hasher) {
                     Some(PureCell::Pos(self.id))
                 } else {
                     None
@@ -65,8 +80,8 @@ impl Cell {
         }
     }
 
-    fn hash_matches(&self) -> bool {
-        xxh3_64(&self.id) == self.hash
+    fn hash_matches<H: HashFamily>(&self, hasher: &H) -> bool {
+        hasher.cell_checksum(&self.id) == self.hash
     }
 }
 
@@ -168,24 +183,51 @@ pub fn distinct_hashes_in_range<const N: usize, const K: usize>(
     })
 }
 
-impl<const N: usize, const K: usize> IBF<N, K> {
+impl<const N: usize, const K: usize, H: HashFamily> IBF<N, K, H> {
+    /// Builds an empty filter using `hasher` for identifiers/checksums. Use
+    /// this (rather than `default`) when `H` carries runtime state, such as
+    /// a session-specific key, that can't come from `Default`.
+    pub fn with_hasher(hasher: H) -> Self {
+        let cells: Box<[Cell]> = vec![Cell::default(); N].into_boxed_slice();
+        Self {
+            cells: cells
+                .try_into()
+                .unwrap_or_else(|_| unreachable!("vec! above allocated exactly N cells")),
+            hasher,
+        }
+    }
+
     pub fn insert<A: AsRef<[u8]>>(&mut self, item: A) {
-        self.insert_hash(blake3::hash(item.as_ref()).as_bytes());
+        self.insert_hash(&self.hasher.id_hash(item.as_ref()));
     }
 
     pub fn remove<A: AsRef<[u8]>>(&mut self, item: A) {
-        self.remove_hash(blake3::hash(item.as_ref()).as_bytes());
+        self.remove_hash(&self.hasher.id_hash(item.as_ref()));
+    }
+
+    /// Like `insert`, but for elements that implement `std::hash::Hash`
+    /// rather than `AsRef<[u8]>`, so callers holding a `HashSet<T>` of
+    /// structured values don't have to serialize `T` first. Both peers must
+    /// use the same `Hash` impl and `HashFamily` for reconciliation to stay
+    /// symmetric.
+    pub fn insert_value<A: std::hash::Hash>(&mut self, item: A) {
+        self.insert_hash(&hash_value(&item, &self.hasher));
+    }
+
+    /// See `insert_value`.
+    pub fn remove_value<A: std::hash::Hash>(&mut self, item: A) {
+        self.remove_hash(&hash_value(&item, &self.hasher));
     }
 
     pub fn insert_hash(&mut self, item_hash: &[u8; HASH_SIZE]) {
         for idx in distinct_hashes_in_range::<N, K>(item_hash) {
-            self.cells[idx as usize] += Cell::new(*item_hash);
+            self.cells[idx as usize] += Cell::new(*item_hash, &self.hasher);
         }
     }
 
     pub fn remove_hash(&mut self, item_hash: &[u8; HASH_SIZE]) {
         for idx in distinct_hashes_in_range::<N, K>(item_hash) {
-            self.cells[idx] -= Cell::new(*item_hash);
+            self.cells[idx] -= Cell::new(*item_hash, &self.hasher);
         }
     }
 
@@ -199,7 +241,7 @@ impl<const N: usize, const K: usize> IBF<N, K> {
 
     pub fn find_pure(&self) -> Option<PureCell> {
         for cell in self.cells.iter() {
-            if let Some(pure_cell) = cell.get_if_pure() {
+            if let Some(pure_cell) = cell.get_if_pure(&self.hasher) {
                 return Some(pure_cell);
             }
         }
@@ -215,8 +257,17 @@ impl<const N: usize, const K: usize> IBF<N, K> {
         true
     }
 
-    pub fn recover(self) -> RecoverIterator<N, K> {
-        RecoverIterator { filter: self }
+    pub fn recover(self) -> RecoverIterator<N, K, H> {
+        let mut candidates = VecDeque::new();
+        for (idx, cell) in self.cells.iter().enumerate() {
+            if cell.get_if_pure(&self.hasher).is_some() {
+                candidates.push_back(idx);
+            }
+        }
+        RecoverIterator {
+            filter: self,
+            candidates,
+        }
     }
 
     pub fn recover_items(self) -> (Vec<PureCell>, Self) {
@@ -227,18 +278,109 @@ impl<const N: usize, const K: usize> IBF<N, K> {
         }
         (vec, iter.filter)
     }
+
+    /// Encodes this filter into a single buffer. See `write_to` to stream
+    /// the encoding instead of building one up front.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_to(&mut out)
+            .expect("writing to a Vec is infallible");
+        out
+    }
+
+    /// Writes the wire encoding directly to `writer`: a version byte, a
+    /// header of `(N, K)` so a decoder can reject mismatched parameters,
+    /// then each cell as `id (32 bytes) | hash (8 bytes LE) | zigzag-varint
+    /// count`. Streams cell-by-cell rather than building a full in-memory
+    /// buffer, so large filters can be sent without doubling their memory
+    /// footprint.
+    pub fn write_to<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut header = vec![WIRE_VERSION];
+        write_varint(&mut header, N as u64);
+        write_varint(&mut header, K as u64);
+        writer.write_all(&header)?;
+
+        let mut cell_buf = Vec::with_capacity(HASH_SIZE + 8 + 10);
+        for cell in self.cells.iter() {
+            cell_buf.clear();
+            cell_buf.extend_from_slice(&cell.id);
+            cell_buf.extend_from_slice(&cell.hash.to_le_bytes());
+            write_varint(&mut cell_buf, zigzag_encode(cell.count));
+            writer.write_all(&cell_buf)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes a filter encoded by `to_bytes`/`write_to`, using `H::default`
+    /// as the decoded filter's hasher.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError>
+    where
+        H: Default,
+    {
+        Self::decode_prefix(bytes).map(|(ibf, _consumed)| ibf)
+    }
+
+    /// Like `from_bytes`, but also returns how many bytes of `bytes` were
+    /// consumed, so callers encoding several filters back-to-back (e.g.
+    /// `Estimator`) can decode them one after another from a shared buffer.
+    pub(crate) fn decode_prefix(bytes: &[u8]) -> Result<(Self, usize), DecodeError>
+    where
+        H: Default,
+    {
+        let version = *bytes.first().ok_or(DecodeError::UnexpectedEof)?;
+        if version != WIRE_VERSION {
+            return Err(DecodeError::UnsupportedVersion { found: version });
+        }
+        let mut pos = 1;
+
+        let (n, consumed) = read_varint(&bytes[pos..])?;
+        pos += consumed;
+        let (k, consumed) = read_varint(&bytes[pos..])?;
+        pos += consumed;
+        if n != N as u64 || k != K as u64 {
+            return Err(DecodeError::IbfParamMismatch {
+                expected: (N as u64, K as u64),
+                found: (n, k),
+            });
+        }
+
+        let mut ibf = Self::default();
+        for cell in ibf.cells.iter_mut() {
+            let id_bytes = bytes
+                .get(pos..pos + HASH_SIZE)
+                .ok_or(DecodeError::UnexpectedEof)?;
+            let mut id = [0u8; HASH_SIZE];
+            id.copy_from_slice(id_bytes);
+            pos += HASH_SIZE;
+
+            let hash_bytes = bytes.get(pos..pos + 8).ok_or(DecodeError::UnexpectedEof)?;
+            let hash = u64::from_le_bytes(hash_bytes.try_into().unwrap());
+            pos += 8;
+
+            let (raw_count, consumed) = read_varint(&bytes[pos..])?;
+            pos += consumed;
+
+            *cell = Cell {
+                id,
+                hash,
+                count: zigzag_decode(raw_count),
+            };
+        }
+
+        Ok((ibf, pos))
+    }
 }
 
-impl<const N: usize, const K: usize> Add<IBF<N, K>> for IBF<N, K> {
-    type Output = IBF<N, K>;
+impl<const N: usize, const K: usize, H: HashFamily> Add<IBF<N, K, H>> for IBF<N, K, H> {
+    type Output = IBF<N, K, H>;
 
-    fn add(mut self, rhs: IBF<N, K>) -> Self::Output {
+    fn add(mut self, rhs: IBF<N, K, H>) -> Self::Output {
         self += rhs;
         self
     }
 }
 
-impl<const N: usize, const K: usize> AddAssign for IBF<N, K> {
+impl<const N: usize, const K: usize, H: HashFamily> AddAssign for IBF<N, K, H> {
     fn add_assign(&mut self, rhs: Self) {
         for i in 0..N {
             self.cells[i] += rhs.cells[i];
@@ -246,16 +388,16 @@ impl<const N: usize, const K: usize> AddAssign for IBF<N, K> {
     }
 }
 
-impl<const N: usize, const K: usize> Sub<IBF<N, K>> for IBF<N, K> {
-    type Output = IBF<N, K>;
+impl<const N: usize, const K: usize, H: HashFamily> Sub<IBF<N, K, H>> for IBF<N, K, H> {
+    type Output = IBF<N, K, H>;
 
-    fn sub(mut self, rhs: IBF<N, K>) -> Self::Output {
+    fn sub(mut self, rhs: IBF<N, K, H>) -> Self::Output {
         self -= rhs;
         self
     }
 }
 
-impl<const N: usize, const K: usize> SubAssign for IBF<N, K> {
+impl<const N: usize, const K: usize, H: HashFamily> SubAssign for IBF<N, K, H> {
     fn sub_assign(&mut self, rhs: Self) {
         for i in 0..N {
             self.cells[i] -= rhs.cells[i];
@@ -263,35 +405,50 @@ impl<const N: usize, const K: usize> SubAssign for IBF<N, K> {
     }
 }
 
-pub struct RecoverIterator<const N: usize, const K: usize> {
-    pub filter: IBF<N, K>,
+pub struct RecoverIterator<const N: usize, const K: usize, H: HashFamily = Blake3Xxh3> {
+    pub filter: IBF<N, K, H>,
+    // Indices that were pure the last time we looked. Re-verified on pop, so
+    // stale entries (cells touched by an earlier peel) are simply skipped and
+    // duplicate pushes are harmless: no per-index "dirty" bookkeeping needed.
+    candidates: VecDeque<usize>,
 }
 
-impl<const N: usize, const K: usize> RecoverIterator<N, K> {
+impl<const N: usize, const K: usize, H: HashFamily> RecoverIterator<N, K, H> {
     pub fn is_fully_recovered(&self) -> bool {
         self.filter.is_empty()
     }
 }
 
-impl<const N: usize, const K: usize> Iterator for RecoverIterator<N, K> {
+impl<const N: usize, const K: usize, H: HashFamily> Iterator for RecoverIterator<N, K, H> {
     type Item = PureCell;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.filter.find_pure().map(|pure| {
+        while let Some(idx) = self.candidates.pop_front() {
+            let Some(pure) = self.filter.cells[idx].get_if_pure(&self.filter.hasher) else {
+                continue;
+            };
+
+            let hash = *pure.get_hash();
             match &pure {
-                PureCell::Pos(hash) => self.filter.remove_hash(hash),
-                PureCell::Neg(hash) => self.filter.insert_hash(hash),
+                PureCell::Pos(_) => self.filter.remove_hash(&hash),
+                PureCell::Neg(_) => self.filter.insert_hash(&hash),
             }
-            pure
-        })
+
+            for touched in distinct_hashes_in_range::<N, K>(&hash) {
+                if self.filter.cells[touched].get_if_pure(&self.filter.hasher).is_some() {
+                    self.candidates.push_back(touched);
+                }
+            }
+
+            return Some(pure);
+        }
+        None
     }
 }
 
-impl<const N: usize, const K: usize> Default for IBF<N, K> {
+impl<const N: usize, const K: usize, H: HashFamily + Default> Default for IBF<N, K, H> {
     fn default() -> Self {
-        Self {
-            cells: [Cell::default(); N],
-        }
+        Self::with_hasher(H::default())
     }
 }
 
@@ -338,22 +495,22 @@ mod ibf_tests {
     proptest! {
         #[test]
         fn sub_itself_is_zero(ibf in ibf_filled_up_to::<80>(100)) {
-            assert!((ibf - ibf).is_empty())
+            assert!((ibf.clone() - ibf).is_empty())
         }
 
         #[test]
         fn sub_is_add_inverse(ibf in ibf_filled_up_to::<80>(100)) {
-            assert!((ibf + (IBF::default() - ibf)).is_empty())
+            assert!((ibf.clone() + (IBF::default() - ibf)).is_empty())
         }
 
         #[test]
         fn add_is_associative((a, b, c) in (ibf_filled_up_to::<80>(100), ibf_filled_up_to::<80>(100), ibf_filled_up_to::<80>(100))) {
-            assert_eq!(((a + b) + c), (a + (b + c)))
+            assert_eq!(((a.clone() + b.clone()) + c.clone()), (a + (b + c)))
         }
 
         #[test]
         fn add_is_commutative((a, b) in (ibf_filled_up_to::<80>(100), ibf_filled_up_to::<80>(100))) {
-            assert_eq!((a + b), (b + a))
+            assert_eq!((a.clone() + b.clone()), (b + a))
         }
 
         #[test]
@@ -383,6 +540,18 @@ mod ibf_tests {
             assert_eq!(count, hs.len());
         }
 
+        #[test]
+        fn insert_value_recovers(values in hash_set(any::<u64>(), 0..40)) {
+            let mut ibf: IBF<80> = IBF::default();
+            for value in values.iter() {
+                ibf.insert_value(*value);
+            }
+
+            let (items, remaining) = ibf.recover_items();
+            assert!(remaining.is_empty());
+            assert_eq!(items.len(), values.len());
+        }
+
         #[test]
         fn distinct_hashing(s in any::<String>()) {
             const R: usize = 10;
@@ -400,5 +569,11 @@ mod ibf_tests {
             let value = map_rand_to_range(xxh3_64(elem.as_bytes()), max);
             assert!(value < max)
         }
+
+        #[test]
+        fn to_bytes_from_bytes_roundtrip(ibf in ibf_filled_up_to::<80>(100)) {
+            let decoded = IBF::from_bytes(&ibf.to_bytes()).unwrap();
+            assert_eq!(ibf, decoded);
+        }
     }
 }
@@ -2,7 +2,10 @@
 #[cfg(test)]
 extern crate proptest;
 
+mod encoding;
+mod hash_family;
 mod ibf;
+mod reconcile;
 mod strata_estimator;
 
 use std::{collections::HashMap, mem};
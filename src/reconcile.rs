@@ -0,0 +1,527 @@
+use std::collections::HashSet;
+use std::io;
+
+use crate::encoding::{read_varint, write_varint, DecodeError, WIRE_VERSION};
+use crate::hash_family::{Blake3Xxh3, HashFamily};
+use crate::ibf::{PureCell, HASH_SIZE, IBF};
+use crate::strata_estimator::Estimator;
+
+/// Number of strata used by the `Estimator` exchanged during reconciliation.
+const STRATA: usize = 16;
+
+/// `test_recoverability` (see `main.rs`) shows an IBF peels reliably up to
+/// roughly half its cells, so we size the filter to a small multiple of the
+/// estimated difference to leave headroom for estimation error.
+const SAFETY_FACTOR: u64 = 2;
+
+const MIN_CELLS: u64 = 16;
+
+fn cells_for_difference(d: u64) -> u64 {
+    (SAFETY_FACTOR * d.max(1)).max(MIN_CELLS)
+}
+
+/// Recovered symmetric difference between two peers' sets, from the local
+/// peer's point of view: `pos` holds identifiers only the local peer has,
+/// `neg` holds identifiers only the other peer has.
+#[derive(Debug, Clone, Default)]
+pub struct Difference {
+    pub pos: HashSet<[u8; HASH_SIZE]>,
+    pub neg: HashSet<[u8; HASH_SIZE]>,
+}
+
+impl Difference {
+    fn push(&mut self, pure: PureCell) {
+        match pure {
+            PureCell::Pos(hash) => {
+                self.pos.insert(hash);
+            }
+            PureCell::Neg(hash) => {
+                self.neg.insert(hash);
+            }
+        }
+    }
+}
+
+/// An `IBF` whose cell count was picked at runtime, from a small fixed
+/// ladder of sizes. `IBF<N, K>` is sized at compile time via a const
+/// generic, so reconciliation (which only learns how large a filter it
+/// needs once it has estimated the difference) dispatches over this enum
+/// instead of picking `N` directly.
+///
+/// Each arm holds its `IBF` directly rather than behind a `Box`: `IBF`
+/// boxes its own cells internally (see `IBF::cells`), so its size doesn't
+/// grow with `N`, and a `SizedIbf`/`Message` moved by value through
+/// `Reconciler::receive` stays cheap without an extra indirection here.
+/// Public so a `Message::Filter` can be matched on and serialized outside
+/// this crate.
+#[derive(Debug, Clone)]
+pub enum SizedIbf<H: HashFamily = Blake3Xxh3> {
+    S16(IBF<16, 4, H>),
+    S32(IBF<32, 4, H>),
+    S64(IBF<64, 4, H>),
+    S128(IBF<128, 4, H>),
+    S256(IBF<256, 4, H>),
+    S512(IBF<512, 4, H>),
+    S1024(IBF<1024, 4, H>),
+    S2048(IBF<2048, 4, H>),
+    S4096(IBF<4096, 4, H>),
+}
+
+impl<H: HashFamily> SizedIbf<H> {
+    /// The largest size on the ladder; reconciliation gives up doubling past
+    /// this rather than growing without bound.
+    const MAX_CELLS: u64 = 4096;
+
+    fn for_cells(cells: u64) -> Self
+    where
+        H: Default,
+    {
+        match cells {
+            c if c <= 16 => Self::S16(IBF::default()),
+            c if c <= 32 => Self::S32(IBF::default()),
+            c if c <= 64 => Self::S64(IBF::default()),
+            c if c <= 128 => Self::S128(IBF::default()),
+            c if c <= 256 => Self::S256(IBF::default()),
+            c if c <= 512 => Self::S512(IBF::default()),
+            c if c <= 1024 => Self::S1024(IBF::default()),
+            c if c <= 2048 => Self::S2048(IBF::default()),
+            _ => Self::S4096(IBF::default()),
+        }
+    }
+
+    fn cells(&self) -> u64 {
+        match self {
+            Self::S16(_) => 16,
+            Self::S32(_) => 32,
+            Self::S64(_) => 64,
+            Self::S128(_) => 128,
+            Self::S256(_) => 256,
+            Self::S512(_) => 512,
+            Self::S1024(_) => 1024,
+            Self::S2048(_) => 2048,
+            Self::S4096(_) => 4096,
+        }
+    }
+
+    fn insert_hash(&mut self, item_hash: &[u8; HASH_SIZE]) {
+        match self {
+            Self::S16(ibf) => ibf.insert_hash(item_hash),
+            Self::S32(ibf) => ibf.insert_hash(item_hash),
+            Self::S64(ibf) => ibf.insert_hash(item_hash),
+            Self::S128(ibf) => ibf.insert_hash(item_hash),
+            Self::S256(ibf) => ibf.insert_hash(item_hash),
+            Self::S512(ibf) => ibf.insert_hash(item_hash),
+            Self::S1024(ibf) => ibf.insert_hash(item_hash),
+            Self::S2048(ibf) => ibf.insert_hash(item_hash),
+            Self::S4096(ibf) => ibf.insert_hash(item_hash),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::S16(ibf) => ibf.is_empty(),
+            Self::S32(ibf) => ibf.is_empty(),
+            Self::S64(ibf) => ibf.is_empty(),
+            Self::S128(ibf) => ibf.is_empty(),
+            Self::S256(ibf) => ibf.is_empty(),
+            Self::S512(ibf) => ibf.is_empty(),
+            Self::S1024(ibf) => ibf.is_empty(),
+            Self::S2048(ibf) => ibf.is_empty(),
+            Self::S4096(ibf) => ibf.is_empty(),
+        }
+    }
+
+    /// Subtracts two same-sized filters. Returns `None` if the peers somehow
+    /// disagreed on the cell count, since `IBF`'s `Sub` only makes sense
+    /// between filters of equal size.
+    fn sub(self, rhs: Self) -> Option<Self> {
+        match (self, rhs) {
+            (Self::S16(a), Self::S16(b)) => Some(Self::S16(a - b)),
+            (Self::S32(a), Self::S32(b)) => Some(Self::S32(a - b)),
+            (Self::S64(a), Self::S64(b)) => Some(Self::S64(a - b)),
+            (Self::S128(a), Self::S128(b)) => Some(Self::S128(a - b)),
+            (Self::S256(a), Self::S256(b)) => Some(Self::S256(a - b)),
+            (Self::S512(a), Self::S512(b)) => Some(Self::S512(a - b)),
+            (Self::S1024(a), Self::S1024(b)) => Some(Self::S1024(a - b)),
+            (Self::S2048(a), Self::S2048(b)) => Some(Self::S2048(a - b)),
+            (Self::S4096(a), Self::S4096(b)) => Some(Self::S4096(a - b)),
+            _ => None,
+        }
+    }
+
+    fn recover_items(self) -> (Vec<PureCell>, Self) {
+        match self {
+            Self::S16(ibf) => {
+                let (items, ibf) = ibf.recover_items();
+                (items, Self::S16(ibf))
+            }
+            Self::S32(ibf) => {
+                let (items, ibf) = ibf.recover_items();
+                (items, Self::S32(ibf))
+            }
+            Self::S64(ibf) => {
+                let (items, ibf) = ibf.recover_items();
+                (items, Self::S64(ibf))
+            }
+            Self::S128(ibf) => {
+                let (items, ibf) = ibf.recover_items();
+                (items, Self::S128(ibf))
+            }
+            Self::S256(ibf) => {
+                let (items, ibf) = ibf.recover_items();
+                (items, Self::S256(ibf))
+            }
+            Self::S512(ibf) => {
+                let (items, ibf) = ibf.recover_items();
+                (items, Self::S512(ibf))
+            }
+            Self::S1024(ibf) => {
+                let (items, ibf) = ibf.recover_items();
+                (items, Self::S1024(ibf))
+            }
+            Self::S2048(ibf) => {
+                let (items, ibf) = ibf.recover_items();
+                (items, Self::S2048(ibf))
+            }
+            Self::S4096(ibf) => {
+                let (items, ibf) = ibf.recover_items();
+                (items, Self::S4096(ibf))
+            }
+        }
+    }
+
+    /// Encodes this filter into a single buffer. See `write_to` to stream
+    /// the encoding instead of building one up front.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_to(&mut out)
+            .expect("writing to a Vec is infallible");
+        out
+    }
+
+    /// Writes the wire encoding directly to `writer`. Delegates to the
+    /// active arm's own `IBF::write_to`, which already encodes `N` in its
+    /// header, so `decode_prefix` can tell which arm to decode into without
+    /// a separate size tag.
+    pub fn write_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Self::S16(ibf) => ibf.write_to(writer),
+            Self::S32(ibf) => ibf.write_to(writer),
+            Self::S64(ibf) => ibf.write_to(writer),
+            Self::S128(ibf) => ibf.write_to(writer),
+            Self::S256(ibf) => ibf.write_to(writer),
+            Self::S512(ibf) => ibf.write_to(writer),
+            Self::S1024(ibf) => ibf.write_to(writer),
+            Self::S2048(ibf) => ibf.write_to(writer),
+            Self::S4096(ibf) => ibf.write_to(writer),
+        }
+    }
+
+    /// Decodes a filter encoded by `to_bytes`/`write_to`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError>
+    where
+        H: Default,
+    {
+        Self::decode_prefix(bytes).map(|(filter, _consumed)| filter)
+    }
+
+    /// Peeks the wire header's cell count to pick the matching ladder arm,
+    /// then lets that arm's own `IBF::decode_prefix` do the rest (including
+    /// re-validating the header).
+    pub(crate) fn decode_prefix(bytes: &[u8]) -> Result<(Self, usize), DecodeError>
+    where
+        H: Default,
+    {
+        let version = *bytes.first().ok_or(DecodeError::UnexpectedEof)?;
+        if version != WIRE_VERSION {
+            return Err(DecodeError::UnsupportedVersion { found: version });
+        }
+        let (n, _consumed) = read_varint(&bytes[1..])?;
+
+        match n {
+            16 => {
+                let (ibf, consumed) = IBF::decode_prefix(bytes)?;
+                Ok((Self::S16(ibf), consumed))
+            }
+            32 => {
+                let (ibf, consumed) = IBF::decode_prefix(bytes)?;
+                Ok((Self::S32(ibf), consumed))
+            }
+            64 => {
+                let (ibf, consumed) = IBF::decode_prefix(bytes)?;
+                Ok((Self::S64(ibf), consumed))
+            }
+            128 => {
+                let (ibf, consumed) = IBF::decode_prefix(bytes)?;
+                Ok((Self::S128(ibf), consumed))
+            }
+            256 => {
+                let (ibf, consumed) = IBF::decode_prefix(bytes)?;
+                Ok((Self::S256(ibf), consumed))
+            }
+            512 => {
+                let (ibf, consumed) = IBF::decode_prefix(bytes)?;
+                Ok((Self::S512(ibf), consumed))
+            }
+            1024 => {
+                let (ibf, consumed) = IBF::decode_prefix(bytes)?;
+                Ok((Self::S1024(ibf), consumed))
+            }
+            2048 => {
+                let (ibf, consumed) = IBF::decode_prefix(bytes)?;
+                Ok((Self::S2048(ibf), consumed))
+            }
+            4096 => {
+                let (ibf, consumed) = IBF::decode_prefix(bytes)?;
+                Ok((Self::S4096(ibf), consumed))
+            }
+            other => Err(DecodeError::UnsupportedSize { found: other }),
+        }
+    }
+}
+
+/// A message exchanged between two peers while reconciling. `Reconciler`
+/// produces these from `start`/`receive` and expects them fed back in via
+/// `receive`. `to_bytes`/`from_bytes` let a caller actually put one on a
+/// transport rather than just passing it in-process.
+#[derive(Debug, Clone)]
+pub enum Message<H: HashFamily = Blake3Xxh3> {
+    /// Sent first, in both directions: lets the other peer compute the
+    /// estimated size of the symmetric difference.
+    Estimator(Estimator<STRATA, H>),
+    /// This peer's filter at the agreed-upon cell count, for the other side
+    /// to subtract against its own and peel.
+    Filter(SizedIbf<H>),
+    /// Peeling didn't fully resolve the difference yet; asks the other peer
+    /// to resend its filter at a larger cell count.
+    Retry { cells: u64 },
+}
+
+impl<H: HashFamily> Message<H> {
+    /// Encodes this message into a single buffer. See `write_to` to stream
+    /// the encoding instead of building one up front.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_to(&mut out)
+            .expect("writing to a Vec is infallible");
+        out
+    }
+
+    /// Writes a leading tag byte (`0` = `Estimator`, `1` = `Filter`, `2` =
+    /// `Retry`) followed by that variant's own encoding.
+    pub fn write_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Self::Estimator(estimator) => {
+                writer.write_all(&[0u8])?;
+                estimator.write_to(writer)
+            }
+            Self::Filter(filter) => {
+                writer.write_all(&[1u8])?;
+                filter.write_to(writer)
+            }
+            Self::Retry { cells } => {
+                let mut buf = vec![2u8];
+                write_varint(&mut buf, *cells);
+                writer.write_all(&buf)
+            }
+        }
+    }
+
+    /// Decodes a message encoded by `to_bytes`/`write_to`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError>
+    where
+        H: Default,
+    {
+        let tag = *bytes.first().ok_or(DecodeError::UnexpectedEof)?;
+        match tag {
+            0 => Ok(Self::Estimator(Estimator::from_bytes(&bytes[1..])?)),
+            1 => Ok(Self::Filter(SizedIbf::from_bytes(&bytes[1..])?)),
+            2 => {
+                let (cells, _consumed) = read_varint(&bytes[1..])?;
+                Ok(Self::Retry { cells })
+            }
+            other => Err(DecodeError::UnsupportedTag { found: other }),
+        }
+    }
+}
+
+/// Drives one side of set reconciliation: estimate the symmetric difference
+/// via `Estimator`, size an `IBF` to match, peel it, and retry with a
+/// doubled cell count if peeling stalls. Elements already recovered in an
+/// earlier round are kept in `difference` and excluded from `local_filter`
+/// on retry, rather than being recomputed from scratch.
+pub struct Reconciler<H: HashFamily = Blake3Xxh3> {
+    local_hashes: Vec<[u8; HASH_SIZE]>,
+    local_estimator: Estimator<STRATA, H>,
+    cells: u64,
+    difference: Difference,
+    /// Local identifiers already peeled out as pure `Pos` cells. Excluded
+    /// from `local_filter` on retry so the enlarged filter's cells go toward
+    /// resolving what's still unknown instead of re-encoding what's already
+    /// in `difference`.
+    recovered: HashSet<[u8; HASH_SIZE]>,
+}
+
+impl<H: HashFamily> Reconciler<H> {
+    pub fn new<A: AsRef<[u8]>>(local_set: &HashSet<A>) -> Self
+    where
+        H: Default,
+    {
+        let mut local_estimator: Estimator<STRATA, H> = Estimator::default();
+        let hasher = local_estimator.strata[0].hasher;
+        let local_hashes = local_set
+            .iter()
+            .map(|elem| {
+                let hash = hasher.id_hash(elem.as_ref());
+                local_estimator.insert_hash(&hash);
+                hash
+            })
+            .collect();
+
+        Self {
+            local_hashes,
+            local_estimator,
+            cells: MIN_CELLS,
+            difference: Difference::default(),
+            recovered: HashSet::new(),
+        }
+    }
+
+    /// The message this peer should send first to kick off reconciliation.
+    pub fn start(&self) -> Message<H> {
+        Message::Estimator(self.local_estimator.clone())
+    }
+
+    fn local_filter(&self) -> SizedIbf<H>
+    where
+        H: Default,
+    {
+        let mut filter = SizedIbf::for_cells(self.cells);
+        for hash in &self.local_hashes {
+            if !self.recovered.contains(hash) {
+                filter.insert_hash(hash);
+            }
+        }
+        filter
+    }
+
+    /// Feeds in a message received from the other peer. Returns the message
+    /// to send back, or `None` once reconciliation has finished: either the
+    /// difference fully resolved, or a filter at `SizedIbf::MAX_CELLS` still
+    /// didn't peel completely, in which case no further retry is requested.
+    pub fn receive(&mut self, message: Message<H>) -> Option<Message<H>>
+    where
+        H: Default,
+    {
+        match message {
+            Message::Estimator(remote_estimator) => {
+                let d = (self.local_estimator.clone() - remote_estimator).estimate();
+                self.cells = cells_for_difference(d);
+                Some(Message::Filter(self.local_filter()))
+            }
+            Message::Retry { cells } => {
+                self.cells = cells;
+                Some(Message::Filter(self.local_filter()))
+            }
+            Message::Filter(remote_filter) => {
+                self.cells = remote_filter.cells();
+                let diffed = self
+                    .local_filter()
+                    .sub(remote_filter)
+                    .expect("both peers agreed on the same cell count");
+                let (items, remaining) = diffed.recover_items();
+                for item in items {
+                    if let PureCell::Pos(hash) = &item {
+                        self.recovered.insert(*hash);
+                    }
+                    self.difference.push(item);
+                }
+
+                if remaining.is_empty() || self.cells >= SizedIbf::<H>::MAX_CELLS {
+                    None
+                } else {
+                    self.cells *= 2;
+                    Some(Message::Retry { cells: self.cells })
+                }
+            }
+        }
+    }
+
+    /// The symmetric difference recovered so far. Can only be complete once
+    /// `receive` has returned `None`, and is still partial if that happened
+    /// because the largest filter size failed to peel completely.
+    pub fn difference(&self) -> &Difference {
+        &self.difference
+    }
+}
+
+/// Reconciles two in-memory sets directly, without a transport: estimates
+/// the difference, peels increasingly large IBFs until recovery succeeds (or
+/// the size ladder is exhausted), and returns what was recovered.
+pub fn reconcile<A: AsRef<[u8]>, H: HashFamily + Default>(
+    local: &HashSet<A>,
+    remote: &HashSet<A>,
+) -> Difference {
+    let mut a = Reconciler::<H>::new(local);
+    let mut b = Reconciler::<H>::new(remote);
+
+    let mut message = a.start();
+    loop {
+        let Some(reply) = b.receive(message) else {
+            break;
+        };
+        let Some(next) = a.receive(reply) else {
+            break;
+        };
+        message = next;
+    }
+
+    a.difference().clone()
+}
+
+#[cfg(test)]
+mod reconcile_tests {
+    use std::collections::HashSet;
+
+    use proptest::{collection::hash_set, prelude::*};
+
+    use super::reconcile;
+    use crate::hash_family::Blake3Xxh3;
+
+    fn partitioned_sets(
+        max_common: usize,
+        max_unique: usize,
+    ) -> impl Strategy<Value = (HashSet<String>, HashSet<String>)> {
+        (
+            hash_set(any::<String>(), 0..max_common),
+            hash_set(any::<String>(), 0..max_unique),
+            hash_set(any::<String>(), 0..max_unique),
+        )
+            .prop_map(|(common, left_only, right_only)| {
+                let mut left = common.clone();
+                left.extend(left_only.difference(&right_only).cloned());
+                let mut right = common;
+                right.extend(right_only.difference(&left_only).cloned());
+                (left, right)
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn reconcile_recovers_symmetric_difference((local, remote) in partitioned_sets(200, 20)) {
+            let difference = reconcile::<_, Blake3Xxh3>(&local, &remote);
+
+            let expected_pos: HashSet<_> = local
+                .difference(&remote)
+                .map(|elem| *blake3::hash(elem.as_bytes()).as_bytes())
+                .collect();
+            let expected_neg: HashSet<_> = remote
+                .difference(&local)
+                .map(|elem| *blake3::hash(elem.as_bytes()).as_bytes())
+                .collect();
+
+            assert_eq!(difference.pos, expected_pos);
+            assert_eq!(difference.neg, expected_neg);
+        }
+    }
+}
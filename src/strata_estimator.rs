@@ -4,25 +4,43 @@ use std::{
     ops::{Add, AddAssign, Sub, SubAssign},
 };
 
+use crate::encoding::{read_varint, write_varint, DecodeError, WIRE_VERSION};
+use crate::hash_family::{hash_value, Blake3Xxh3, HashFamily};
 use crate::ibf::{HASH_SIZE, IBF};
 
 const N: usize = 80;
+// The IBF arity each stratum uses; not configurable today, but named so the
+// wire header has something to record alongside N and S.
+const STRATUM_K: usize = 4;
 
-#[derive(Debug, Clone, Copy)]
-pub struct Estimator<const S: usize = 16> {
-    pub strata: [IBF<N>; S],
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Estimator<const S: usize = 16, H: HashFamily = Blake3Xxh3> {
+    pub strata: [IBF<N, STRATUM_K, H>; S],
 }
 
-impl<const S: usize> Default for Estimator<S> {
+impl<const S: usize, H: HashFamily + Default> Default for Estimator<S, H> {
     fn default() -> Self {
+        Self::with_hasher(H::default())
+    }
+}
+
+impl<const S: usize, H: HashFamily> Estimator<S, H> {
+    /// Builds an empty estimator whose strata all share `hasher`. Use this
+    /// (rather than `default`) when `H` carries runtime state, such as a
+    /// session-specific key, that can't come from `Default`.
+    pub fn with_hasher(hasher: H) -> Self {
+        // `H` is `Copy`, so the closure can build each stratum's own
+        // `IBF::with_hasher` without consuming `hasher` (which `IBF` itself
+        // no longer is, now that its cells are heap-allocated).
         Self {
-            strata: [IBF::<N>::default(); S],
+            strata: std::array::from_fn(|_| IBF::<N, STRATUM_K, H>::with_hasher(hasher)),
         }
     }
-}
 
-impl<const S: usize> Estimator<S> {
-    pub fn of<A: AsRef<[u8]>>(set: &HashSet<A>) -> Self {
+    pub fn of<A: AsRef<[u8]>>(set: &HashSet<A>) -> Self
+    where
+        H: Default,
+    {
         let mut estimator = Self::default();
         for elem in set {
             estimator.insert(elem);
@@ -35,7 +53,8 @@ impl<const S: usize> Estimator<S> {
     }
 
     pub fn insert<A: AsRef<[u8]>>(&mut self, item: A) {
-        self.insert_hash(blake3::hash(item.as_ref()).as_bytes());
+        let hash = self.strata[0].hasher.id_hash(item.as_ref());
+        self.insert_hash(&hash);
     }
 
     pub fn insert_hash(&mut self, item_hash: &[u8; HASH_SIZE]) {
@@ -43,13 +62,27 @@ impl<const S: usize> Estimator<S> {
     }
 
     pub fn remove<A: AsRef<[u8]>>(&mut self, item: A) {
-        self.remove_hash(blake3::hash(item.as_ref()).as_bytes());
+        let hash = self.strata[0].hasher.id_hash(item.as_ref());
+        self.remove_hash(&hash);
     }
 
     pub fn remove_hash(&mut self, item_hash: &[u8; HASH_SIZE]) {
         self.strata[Self::bucket_for_hash(item_hash)].remove_hash(item_hash)
     }
 
+    /// Like `insert`, but for elements that implement `std::hash::Hash`
+    /// rather than `AsRef<[u8]>`. See `IBF::insert_value`.
+    pub fn insert_value<A: std::hash::Hash>(&mut self, item: A) {
+        let hash = hash_value(&item, &self.strata[0].hasher);
+        self.insert_hash(&hash);
+    }
+
+    /// See `insert_value`.
+    pub fn remove_value<A: std::hash::Hash>(&mut self, item: A) {
+        let hash = hash_value(&item, &self.strata[0].hasher);
+        self.remove_hash(&hash);
+    }
+
     pub fn estimate(&self) -> u64 {
         let mut count = 0;
 
@@ -58,7 +91,7 @@ impl<const S: usize> Estimator<S> {
                 break;
             }
 
-            let ibf = self.strata[level as usize];
+            let ibf = self.strata[level as usize].clone();
             let mut iter = ibf.recover();
             let mut recovered = 0;
             while let Some(_) = iter.next() {
@@ -75,18 +108,81 @@ impl<const S: usize> Estimator<S> {
 
         return count;
     }
+
+    /// Encodes this estimator into a single buffer. See `write_to` to
+    /// stream the encoding instead of building one up front.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_to(&mut out)
+            .expect("writing to a Vec is infallible");
+        out
+    }
+
+    /// Writes the wire encoding directly to `writer`: a version byte, a
+    /// header of `(N, K, S)` so a decoder can reject mismatched parameters,
+    /// then each stratum's own `IBF` encoding concatenated in order.
+    pub fn write_to<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut header = vec![WIRE_VERSION];
+        write_varint(&mut header, N as u64);
+        write_varint(&mut header, STRATUM_K as u64);
+        write_varint(&mut header, S as u64);
+        writer.write_all(&header)?;
+
+        for stratum in &self.strata {
+            stratum.write_to(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes an estimator encoded by `to_bytes`/`write_to`, using
+    /// `H::default` as the decoded estimator's hasher.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError>
+    where
+        H: Default,
+    {
+        let version = *bytes.first().ok_or(DecodeError::UnexpectedEof)?;
+        if version != WIRE_VERSION {
+            return Err(DecodeError::UnsupportedVersion { found: version });
+        }
+        let mut pos = 1;
+
+        let (n, consumed) = read_varint(&bytes[pos..])?;
+        pos += consumed;
+        let (k, consumed) = read_varint(&bytes[pos..])?;
+        pos += consumed;
+        let (s, consumed) = read_varint(&bytes[pos..])?;
+        pos += consumed;
+        if n != N as u64 || k != STRATUM_K as u64 || s != S as u64 {
+            return Err(DecodeError::EstimatorParamMismatch {
+                expected: (N as u64, STRATUM_K as u64, S as u64),
+                found: (n, k, s),
+            });
+        }
+
+        let mut estimator = Self::default();
+        for stratum in estimator.strata.iter_mut() {
+            let (decoded, consumed) = IBF::decode_prefix(&bytes[pos..])?;
+            *stratum = decoded;
+            pos += consumed;
+        }
+
+        Ok(estimator)
+    }
 }
 
-impl<const S: usize> AddAssign for Estimator<S> {
+impl<const S: usize, H: HashFamily> AddAssign for Estimator<S, H> {
     fn add_assign(&mut self, rhs: Self) {
-        for i in 0..S {
-            self.strata[i] += rhs.strata[i];
+        // `rhs.strata[i]` can't be moved out of an array by runtime index
+        // (IBF isn't Copy), so consume `rhs.strata` by value via its array
+        // `IntoIterator` impl instead of indexing into it.
+        for (stratum, rhs_stratum) in self.strata.iter_mut().zip(rhs.strata) {
+            *stratum += rhs_stratum;
         }
     }
 }
 
-impl<const S: usize> Add for Estimator<S> {
-    type Output = Estimator<S>;
+impl<const S: usize, H: HashFamily> Add for Estimator<S, H> {
+    type Output = Estimator<S, H>;
 
     fn add(mut self, rhs: Self) -> Self::Output {
         self += rhs;
@@ -94,16 +190,16 @@ impl<const S: usize> Add for Estimator<S> {
     }
 }
 
-impl<const S: usize> SubAssign for Estimator<S> {
+impl<const S: usize, H: HashFamily> SubAssign for Estimator<S, H> {
     fn sub_assign(&mut self, rhs: Self) {
-        for i in 0..S {
-            self.strata[i] -= rhs.strata[i];
+        for (stratum, rhs_stratum) in self.strata.iter_mut().zip(rhs.strata) {
+            *stratum -= rhs_stratum;
         }
     }
 }
 
-impl<const S: usize> Sub for Estimator<S> {
-    type Output = Estimator<S>;
+impl<const S: usize, H: HashFamily> Sub for Estimator<S, H> {
+    type Output = Estimator<S, H>;
 
     fn sub(mut self, rhs: Self) -> Self::Output {
         self -= rhs;
@@ -200,5 +296,25 @@ mod strata_estimator_tests {
             let difference = (estimated - actual).abs();
             assert!(difference <= actual * error_margin);
         }
+
+        #[test]
+        fn to_bytes_from_bytes_roundtrip(set in hash_set(any::<String>(), 0..1_000)) {
+            let estimator = Estimator::<16>::of(&set);
+            let decoded = Estimator::<16>::from_bytes(&estimator.to_bytes()).unwrap();
+            assert_eq!(estimator, decoded);
+        }
+
+        #[test]
+        fn insert_value_estimate_within_bounds(values in hash_set(any::<u64>(), 0..1_000)) {
+            let error_margin = 1.5;
+            let mut estimator = Estimator::<16>::default();
+            for value in values.iter() {
+                estimator.insert_value(*value);
+            }
+            let estimated = estimator.estimate() as f64;
+            let actual = values.len() as f64;
+            let difference = (estimated - actual).abs();
+            assert!(difference <= actual * error_margin);
+        }
     }
 }